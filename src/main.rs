@@ -1,14 +1,97 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use clap::{Arg, Command};
-use log::{debug, LevelFilter, SetLoggerError};
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use log::{debug, error, warn, LevelFilter, SetLoggerError};
 use log::{Level, Metadata, Record};
 use mdbook::book::BookItem;
 use mdbook::preprocess::CmdPreprocessor;
 use regex::{CaptureMatches, Captures, Regex};
 
+/// Default hidden feature(s) when `[preprocessor.hide-feature]` sets none.
+const DEFAULT_HIDDEN_FEATURES: &[&str] = &["test"];
+
+/// Recursive `{{#includehidetest}}` expansion stops past this depth, so a
+/// cyclic chain of includes terminates instead of recursing forever.
+const MAX_LINK_NESTED_DEPTH: usize = 10;
+
+/// How a cfg-gated block that references a hidden feature is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Prefix the block with mdBook's `# ` hide marker, so it's collapsed in
+    /// the rendered page but still fed to rustdoc test runs.
+    Hide,
+    /// Remove the block (attribute through closing token, and its leading
+    /// blank line) entirely, for outputs where it shouldn't appear at all.
+    Strip,
+    /// Pass the block through unmodified.
+    Show,
+}
+
+impl Mode {
+    fn parse(s: &str) -> Option<Mode> {
+        match s {
+            "hide" => Some(Mode::Hide),
+            "strip" => Some(Mode::Strip),
+            "show" => Some(Mode::Show),
+            _ => None,
+        }
+    }
+}
+
+/// Book-wide settings read from `[preprocessor.hide-feature]` in `book.toml`.
+pub(crate) struct PreprocessorConfig {
+    /// Feature names (or `"test"` for bare `#[cfg(test)]`) to hide.
+    features: Vec<String>,
+    /// Whether to parse includes with `syn` and hide exact item spans
+    /// instead of counting braces. Only meaningful for Rust source.
+    rust_aware: bool,
+    /// How a matched block is rendered by default; overridable per
+    /// directive with `mode=strip`/`mode=show`/`mode=hide`.
+    mode: Mode,
+}
+
+impl PreprocessorConfig {
+    fn from_context(ctx: &mdbook::preprocess::PreprocessorContext) -> Self {
+        let cfg = ctx.config.get_preprocessor("hide-feature");
+
+        let features = cfg
+            .and_then(|cfg| cfg.get("features"))
+            .and_then(|features| features.as_array())
+            .map(|features| {
+                features
+                    .iter()
+                    .filter_map(|feature| feature.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_else(|| {
+                DEFAULT_HIDDEN_FEATURES
+                    .iter()
+                    .map(|feature| feature.to_string())
+                    .collect()
+            });
+
+        let rust_aware = cfg
+            .and_then(|cfg| cfg.get("rust_aware"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        let mode = cfg
+            .and_then(|cfg| cfg.get("mode"))
+            .and_then(|v| v.as_str())
+            .and_then(Mode::parse)
+            .unwrap_or(Mode::Hide);
+
+        PreprocessorConfig {
+            features,
+            rust_aware,
+            mode,
+        }
+    }
+}
+
 static LOGGER: SimpleLogger = SimpleLogger;
 
 pub fn init() -> Result<(), SetLoggerError> {
@@ -41,27 +124,126 @@ pub fn make_app() -> Command {
         )
 }
 
-/// Filters out blocks of code that are enclosed in #[cfg(feature = "feature_name")]
-fn filter_features(contents: &str, feature_name: &str) -> String {
+/// A parsed `#[cfg(...)]` predicate. We don't evaluate it to a boolean;
+/// we only need to know whether it *references* one of the configured
+/// hidden features anywhere in the tree, per [`references_hidden_feature`].
+#[derive(Debug, Clone, PartialEq)]
+enum CfgPredicate {
+    Feature(String),
+    Test,
+    All(Vec<CfgPredicate>),
+    Any(Vec<CfgPredicate>),
+    Not(Box<CfgPredicate>),
+    /// Anything else (`target_os = "..."`, `unix`, ...): never matches.
+    Other,
+}
+
+/// Splits `a, b(c, d), e` on its top-level commas, ignoring commas nested
+/// inside parentheses.
+fn split_top_level_commas(input: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(input[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(input[start..].trim());
+    parts
+}
+
+/// Parses the inside of a `#[cfg(...)]` attribute: `all(...)`, `any(...)`,
+/// `not(...)`, bare `test`, and `feature = "name"`.
+fn parse_cfg_predicate(input: &str) -> CfgPredicate {
+    let input = input.trim();
+
+    if input == "test" {
+        return CfgPredicate::Test;
+    }
+
+    if let Some(name) = input
+        .strip_prefix("feature")
+        .map(str::trim_start)
+        .and_then(|s| s.strip_prefix('='))
+        .map(|s| s.trim().trim_matches('"'))
+    {
+        return CfgPredicate::Feature(name.to_string());
+    }
+
+    for (prefix, depth_zero) in [("all", false), ("any", false), ("not", true)] {
+        let Some(rest) = input.strip_prefix(prefix).map(str::trim_start) else {
+            continue;
+        };
+        let Some(inner) = rest.strip_prefix('(').and_then(|s| s.strip_suffix(')')) else {
+            continue;
+        };
+
+        return if depth_zero {
+            CfgPredicate::Not(Box::new(parse_cfg_predicate(inner)))
+        } else {
+            let children = split_top_level_commas(inner)
+                .into_iter()
+                .map(parse_cfg_predicate)
+                .collect();
+            if prefix == "all" {
+                CfgPredicate::All(children)
+            } else {
+                CfgPredicate::Any(children)
+            }
+        };
+    }
+
+    CfgPredicate::Other
+}
+
+/// Whether `predicate` mentions any of `hidden_features` anywhere in its
+/// tree (ignoring `not(...)`'s negation, since we only care about presence).
+fn references_hidden_feature(predicate: &CfgPredicate, hidden_features: &[String]) -> bool {
+    match predicate {
+        CfgPredicate::Feature(name) => hidden_features.iter().any(|f| f == name),
+        CfgPredicate::Test => hidden_features.iter().any(|f| f == "test"),
+        CfgPredicate::All(preds) | CfgPredicate::Any(preds) => preds
+            .iter()
+            .any(|p| references_hidden_feature(p, hidden_features)),
+        CfgPredicate::Not(inner) => references_hidden_feature(inner, hidden_features),
+        CfgPredicate::Other => false,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref RE_CFG_ATTR: Regex = Regex::new(r"^\s*#\s*\[cfg\((?P<pred>.*)\)\]\s*$").unwrap();
+    static ref RE_OPEN_BRACE: Regex = Regex::new(r"\{").unwrap();
+    static ref RE_CLOSE_BRACE: Regex = Regex::new(r"\}").unwrap();
+}
+
+/// Filters out blocks of code gated by a `#[cfg(...)]` attribute that
+/// references any of `hidden_features` (a bare feature name hides
+/// `#[cfg(feature = "name")]`; `"test"` also hides bare `#[cfg(test)]`), per
+/// `mode`. Callers should handle `Mode::Show` themselves; it's a no-op here.
+fn filter_features(contents: &str, hidden_features: &[String], mode: Mode) -> String {
+    if mode == Mode::Show {
+        return contents.to_string();
+    }
     let mut result = String::new();
     let mut skip = false;
     let mut brace_count = 0;
+    let lines: Vec<&str> = contents.lines().collect();
 
-    let re_start = regex::Regex::new(&format!(
-        r#"^\s*#\s*\[cfg\(feature = "{}"\)\]"#,
-        feature_name
-    ))
-    .unwrap();
-    let re_open_brace = regex::Regex::new(r"\{").unwrap();
-    let re_close_brace = regex::Regex::new(r"\}").unwrap();
-
-    for line in contents.lines() {
+    for (i, &line) in lines.iter().enumerate() {
         if skip {
             // Count braces only if we are inside a skipped section
-            if re_open_brace.is_match(line) {
+            if RE_OPEN_BRACE.is_match(line) {
                 brace_count += line.matches('{').count();
             }
-            if re_close_brace.is_match(line) {
+            if RE_CLOSE_BRACE.is_match(line) {
                 brace_count -= line.matches('}').count();
             }
 
@@ -70,18 +252,36 @@ fn filter_features(contents: &str, feature_name: &str) -> String {
                 skip = false;
             }
 
-            result.push_str(&format!("# {line}"));
-            result.push('\n');
+            if mode != Mode::Strip {
+                result.push_str(&format!("# {line}"));
+                result.push('\n');
+            }
             continue;
         }
 
-        // Check if the line contains the start of a cfg feature block
-        if re_start.is_match(line) {
-            skip = true;
+        // Check if the line contains the start of a matching cfg block
+        if let Some(caps) = RE_CFG_ATTR.captures(line) {
+            let predicate = parse_cfg_predicate(&caps["pred"]);
+            if references_hidden_feature(&predicate, hidden_features) {
+                skip = true;
 
-            result.push_str(&format!("# {line}"));
-            result.push('\n');
-            continue;
+                // Look at the actual preceding source line rather than a
+                // flag carried across iterations, so a skip region doesn't
+                // leave stale state for the next one (see hide_byte_spans,
+                // which resolves this the same way).
+                let prev_blank = i > 0 && lines[i - 1].trim().is_empty();
+                if mode == Mode::Strip && prev_blank {
+                    // drop the blank line we already emitted before this block
+                    let emitted_blank = format!("{}\n", lines[i - 1]);
+                    if result.ends_with(&emitted_blank) {
+                        result.truncate(result.len() - emitted_blank.len());
+                    }
+                } else if mode != Mode::Strip {
+                    result.push_str(&format!("# {line}"));
+                    result.push('\n');
+                }
+                continue;
+            }
         }
 
         // Add the line to the result if not skipping
@@ -92,7 +292,456 @@ fn filter_features(contents: &str, feature_name: &str) -> String {
     result
 }
 
-pub fn replace_all<P: AsRef<Path>>(s: &str, path: P) -> Result<String> {
+#[cfg(test)]
+mod filter_features_tests {
+    use super::*;
+
+    #[test]
+    fn strip_mode_preserves_blank_line_between_adjacent_stripped_blocks() {
+        let contents = concat!(
+            "fn a() {}\n",
+            "\n",
+            "#[cfg(feature = \"test\")]\n",
+            "fn b() {}\n",
+            "#[cfg(feature = \"test\")]\n",
+            "fn c() {}\n",
+            "\n",
+            "fn d() {}\n",
+        );
+
+        let result = filter_features(contents, &["test".to_string()], Mode::Strip);
+
+        assert_eq!(result, "fn a() {}\n\nfn d() {}\n");
+    }
+}
+
+/// The `#[cfg(...)]`-bearing `syn::Item` variants we hide. Each arm just
+/// projects out the item's `attrs`; anything not listed here (i.e.
+/// `Item::Verbatim`, tokens syn can't parse as a known item) gets no attrs
+/// and is left alone.
+fn item_attrs(item: &syn::Item) -> &[syn::Attribute] {
+    use syn::Item;
+    match item {
+        Item::Const(i) => &i.attrs,
+        Item::Enum(i) => &i.attrs,
+        Item::ExternCrate(i) => &i.attrs,
+        Item::Fn(i) => &i.attrs,
+        Item::ForeignMod(i) => &i.attrs,
+        Item::Impl(i) => &i.attrs,
+        Item::Macro(i) => &i.attrs,
+        Item::Mod(i) => &i.attrs,
+        Item::Static(i) => &i.attrs,
+        Item::Struct(i) => &i.attrs,
+        Item::Trait(i) => &i.attrs,
+        Item::TraitAlias(i) => &i.attrs,
+        Item::Type(i) => &i.attrs,
+        Item::Union(i) => &i.attrs,
+        Item::Use(i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+/// Converts a 1-based `proc_macro2::LineColumn` (0-based column, in chars)
+/// into a byte offset into `contents`.
+fn line_col_to_byte_offset(contents: &str, pos: proc_macro2::LineColumn) -> usize {
+    let mut offset = 0;
+    for (i, line) in contents.split('\n').enumerate() {
+        if i + 1 == pos.line {
+            return offset
+                + line
+                    .char_indices()
+                    .nth(pos.column)
+                    .map_or(line.len(), |(b, _)| b);
+        }
+        offset += line.len() + 1;
+    }
+    contents.len()
+}
+
+/// Applies `mode` to every line touched by one of `byte_spans`: `Hide`
+/// prefixes with mdBook's `# ` marker, `Strip` drops the lines (and a
+/// directly preceding blank line), `Show` leaves everything untouched.
+fn hide_byte_spans(contents: &str, byte_spans: &[(usize, usize)], mode: Mode) -> String {
+    use std::collections::BTreeSet;
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut hidden_lines: BTreeSet<usize> = BTreeSet::new();
+
+    for &(start, end) in byte_spans {
+        let mut start_line = contents[..start].matches('\n').count();
+        let end_line = contents[..end.max(start)].matches('\n').count();
+        if mode == Mode::Strip && start_line > 0 && lines[start_line - 1].trim().is_empty() {
+            start_line -= 1;
+        }
+        hidden_lines.extend(start_line..=end_line);
+    }
+
+    let mut result = String::new();
+    for (i, line) in lines.into_iter().enumerate() {
+        if hidden_lines.contains(&i) {
+            match mode {
+                Mode::Hide => {
+                    result.push_str(&format!("# {line}"));
+                    result.push('\n');
+                }
+                Mode::Strip => {}
+                Mode::Show => {
+                    result.push_str(line);
+                    result.push('\n');
+                }
+            }
+        } else {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// A syntax-aware alternative to [`filter_features`]: parses `contents` as a
+/// Rust file with `syn` and applies `mode` to every line touched by the byte
+/// span (attribute through the item's closing token) of every top-level item
+/// whose `#[cfg(...)]` references a hidden feature; other code sharing one of
+/// those lines is treated the same. Returns `None` if `contents`
+/// doesn't parse as Rust, so callers can fall back to the line-based scanner.
+fn filter_features_syn(contents: &str, hidden_features: &[String], mode: Mode) -> Option<String> {
+    let file = syn::parse_file(contents).ok()?;
+    let mut spans = Vec::new();
+
+    for item in &file.items {
+        for attr in item_attrs(item) {
+            if !attr.path().is_ident("cfg") {
+                continue;
+            }
+            let Ok(tokens) = attr.parse_args::<proc_macro2::TokenStream>() else {
+                continue;
+            };
+            let predicate = parse_cfg_predicate(&tokens.to_string());
+            if !references_hidden_feature(&predicate, hidden_features) {
+                continue;
+            }
+
+            use syn::spanned::Spanned;
+            let start = line_col_to_byte_offset(contents, attr.span().start());
+            let end = line_col_to_byte_offset(contents, item.span().end());
+            spans.push((start, end));
+        }
+    }
+
+    Some(hide_byte_spans(contents, &spans, mode))
+}
+
+#[cfg(test)]
+mod filter_features_syn_tests {
+    use super::*;
+
+    #[test]
+    fn hides_exact_byte_span_of_multiline_cfg_gated_item() {
+        let contents = concat!(
+            "fn a() {}\n",
+            "\n",
+            "#[cfg(feature = \"test\")]\n",
+            "fn b() {\n",
+            "    let s = \"}\"; // brace inside a string literal\n",
+            "}\n",
+            "\n",
+            "fn c() {}\n",
+        );
+
+        let result = filter_features_syn(contents, &["test".to_string()], Mode::Hide)
+            .expect("valid Rust source should parse");
+
+        assert_eq!(
+            result,
+            concat!(
+                "fn a() {}\n",
+                "\n",
+                "# #[cfg(feature = \"test\")]\n",
+                "# fn b() {\n",
+                "#     let s = \"}\"; // brace inside a string literal\n",
+                "# }\n",
+                "\n",
+                "fn c() {}\n",
+            )
+        );
+    }
+}
+
+/// Hides cfg-gated blocks referencing `hidden_features` according to `mode`.
+/// Uses the syntax-aware `syn` path when `rust_aware` is set and `contents`
+/// parses as Rust, falling back to the brace-counting line scanner otherwise.
+fn hide_features(
+    contents: &str,
+    hidden_features: &[String],
+    rust_aware: bool,
+    mode: Mode,
+) -> String {
+    if mode == Mode::Show {
+        return contents.to_string();
+    }
+    if rust_aware {
+        if let Some(result) = filter_features_syn(contents, hidden_features, mode) {
+            return result;
+        }
+    }
+    filter_features(contents, hidden_features, mode)
+}
+
+/// A line range or anchor selecting a subset of an included file, using the
+/// same `file.rs:10:20` / `file.rs:anchor_name` syntax as mdBook's own
+/// `{{#include}}`.
+#[derive(PartialOrd, PartialEq, Debug, Clone)]
+enum Selection {
+    LineRange(LineRange),
+    Anchor(String),
+}
+
+/// A 1-based, inclusive line range. `start: None` means "from line 1" and
+/// `end: None` means "to EOF".
+#[derive(PartialOrd, PartialEq, Debug, Clone)]
+struct LineRange {
+    start: Option<usize>,
+    end: Option<usize>,
+}
+
+/// Splits a `path[:start:end]` / `path[:anchor]` token into its file path and
+/// optional selection. The path itself is assumed not to contain `:`, which
+/// mdBook's own link syntax assumes too.
+fn parse_selector(raw: &str) -> (PathBuf, Option<Selection>) {
+    lazy_static::lazy_static! {
+        static ref RE_SELECTOR: Regex = Regex::new(
+            r"^(?P<path>.+?)(?:(?::(?P<start>\d*):(?P<end>\d*))|(?::(?P<anchor>[^:]+)))?$"
+        )
+        .unwrap();
+    }
+
+    let caps = RE_SELECTOR
+        .captures(raw)
+        .expect("RE_SELECTOR matches any string, since its trailing group is optional");
+    let path = PathBuf::from(&caps["path"]);
+
+    if caps.name("start").is_some() || caps.name("end").is_some() {
+        let start = caps
+            .name("start")
+            .filter(|m| !m.as_str().is_empty())
+            .and_then(|m| m.as_str().parse().ok());
+        let end = caps
+            .name("end")
+            .filter(|m| !m.as_str().is_empty())
+            .and_then(|m| m.as_str().parse().ok());
+        (path, Some(Selection::LineRange(LineRange { start, end })))
+    } else if let Some(anchor) = caps.name("anchor") {
+        (path, Some(Selection::Anchor(anchor.as_str().to_string())))
+    } else {
+        (path, None)
+    }
+}
+
+/// Keeps 1-based inclusive lines `start..=end` of `contents`, with an open
+/// `start` meaning "from line 1" and an open `end` meaning "to EOF".
+fn take_lines(contents: &str, start: Option<usize>, end: Option<usize>) -> String {
+    let lines: Vec<&str> = contents.lines().collect();
+    let start_idx = start
+        .map(|n| n.saturating_sub(1))
+        .unwrap_or(0)
+        .min(lines.len());
+    let end_idx = end.unwrap_or(lines.len()).min(lines.len());
+
+    if start_idx >= end_idx {
+        return String::new();
+    }
+
+    let mut result = lines[start_idx..end_idx].join("\n");
+    result.push('\n');
+    result
+}
+
+lazy_static::lazy_static! {
+    // Matches `// ANCHOR: name`, `# ANCHOR: name`, `/* ANCHOR: name */` and
+    // `<!-- ANCHOR: name -->`, and the `_END` counterpart.
+    static ref RE_ANCHOR_START: Regex =
+        Regex::new(r"(?m)^\s*(?://|#|/\*|<!--)\s*ANCHOR:\s*(?P<name>[\w.-]+)\s*(?:\*/|-->)?\s*$")
+            .unwrap();
+    static ref RE_ANCHOR_END: Regex =
+        Regex::new(r"(?m)^\s*(?://|#|/\*|<!--)\s*ANCHOR_END:\s*(?P<name>[\w.-]+)\s*(?:\*/|-->)?\s*$")
+            .unwrap();
+}
+
+/// Keeps only the lines strictly between a `// ANCHOR: name` / `// ANCHOR_END: name`
+/// pair, stripping every anchor marker line (including nested ones for other
+/// anchors) from the output. Returns `None` if the anchor is never opened.
+fn take_anchored_lines(contents: &str, anchor: &str) -> Option<String> {
+    let mut in_anchor = false;
+    let mut found = false;
+    let mut out = Vec::new();
+
+    for line in contents.lines() {
+        if let Some(caps) = RE_ANCHOR_START.captures(line) {
+            if !in_anchor && &caps["name"] == anchor {
+                in_anchor = true;
+                found = true;
+            }
+            continue;
+        }
+        if let Some(caps) = RE_ANCHOR_END.captures(line) {
+            if in_anchor && &caps["name"] == anchor {
+                in_anchor = false;
+            }
+            continue;
+        }
+        if in_anchor {
+            out.push(line);
+        }
+    }
+
+    if !found {
+        return None;
+    }
+
+    let mut result = out.join("\n");
+    result.push('\n');
+    Some(result)
+}
+
+#[cfg(test)]
+mod selection_tests {
+    use super::*;
+
+    #[test]
+    fn parse_selector_line_range() {
+        let (path, selection) = parse_selector("file.rs:10:20");
+        assert_eq!(path, PathBuf::from("file.rs"));
+        assert_eq!(
+            selection,
+            Some(Selection::LineRange(LineRange {
+                start: Some(10),
+                end: Some(20),
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_selector_open_start() {
+        let (path, selection) = parse_selector("file.rs::20");
+        assert_eq!(path, PathBuf::from("file.rs"));
+        assert_eq!(
+            selection,
+            Some(Selection::LineRange(LineRange {
+                start: None,
+                end: Some(20),
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_selector_open_end() {
+        let (path, selection) = parse_selector("file.rs:10:");
+        assert_eq!(path, PathBuf::from("file.rs"));
+        assert_eq!(
+            selection,
+            Some(Selection::LineRange(LineRange {
+                start: Some(10),
+                end: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_selector_anchor() {
+        let (path, selection) = parse_selector("file.rs:mysnippet");
+        assert_eq!(path, PathBuf::from("file.rs"));
+        assert_eq!(selection, Some(Selection::Anchor("mysnippet".to_string())));
+    }
+
+    #[test]
+    fn parse_selector_no_selection() {
+        let (path, selection) = parse_selector("file.rs");
+        assert_eq!(path, PathBuf::from("file.rs"));
+        assert_eq!(selection, None);
+    }
+
+    #[test]
+    fn take_lines_closed_range_is_one_based_inclusive() {
+        let contents = "one\ntwo\nthree\nfour\nfive\n";
+        assert_eq!(take_lines(contents, Some(2), Some(4)), "two\nthree\nfour\n");
+    }
+
+    #[test]
+    fn take_lines_open_start_means_from_line_1() {
+        let contents = "one\ntwo\nthree\n";
+        assert_eq!(take_lines(contents, None, Some(2)), "one\ntwo\n");
+    }
+
+    #[test]
+    fn take_lines_open_end_means_to_eof() {
+        let contents = "one\ntwo\nthree\n";
+        assert_eq!(take_lines(contents, Some(2), None), "two\nthree\n");
+    }
+
+    #[test]
+    fn take_anchored_lines_strips_markers_including_nested_ones() {
+        let contents = concat!(
+            "before\n",
+            "// ANCHOR: outer\n",
+            "kept one\n",
+            "// ANCHOR: inner\n",
+            "kept two\n",
+            "// ANCHOR_END: inner\n",
+            "kept three\n",
+            "// ANCHOR_END: outer\n",
+            "after\n",
+        );
+
+        assert_eq!(
+            take_anchored_lines(contents, "outer"),
+            Some("kept one\nkept two\nkept three\n".to_string())
+        );
+    }
+
+    #[test]
+    fn take_anchored_lines_not_found_returns_none() {
+        let contents = "// ANCHOR: a\nkept\n// ANCHOR_END: a\n";
+        assert_eq!(take_anchored_lines(contents, "nonexistent"), None);
+    }
+}
+
+/// Parses trailing `key=value` args the way mdbook-template does: space
+/// separated, with each value running up to the next ` key=` or the end of
+/// the string (so values may themselves contain spaces).
+fn parse_args(raw: &str) -> BTreeMap<String, String> {
+    lazy_static::lazy_static! {
+        static ref RE_KEY: Regex = Regex::new(r"(?:^|\s)(?P<key>[^\s=]+)=").unwrap();
+    }
+
+    let keys: Vec<Captures> = RE_KEY.captures_iter(raw).collect();
+    let mut args = BTreeMap::new();
+
+    for (i, caps) in keys.iter().enumerate() {
+        let value_start = caps.get(0).unwrap().end();
+        let value_end = keys
+            .get(i + 1)
+            .map(|next| next.get(0).unwrap().start())
+            .unwrap_or(raw.len());
+        args.insert(
+            caps["key"].to_string(),
+            raw[value_start..value_end].trim().to_string(),
+        );
+    }
+
+    args
+}
+
+/// Expands every `{{#includehidetest ...}}` directive in `s`. `context` is a
+/// human-readable label (the enclosing chapter's name) used in error logs. A
+/// directive whose include can't be read is logged and left untouched in the
+/// output rather than aborting the whole render.
+pub(crate) fn replace_all<P: AsRef<Path>>(
+    s: &str,
+    path: P,
+    config: &PreprocessorConfig,
+    depth: usize,
+    context: &str,
+) -> String {
     // When replacing one thing in a string by something with a different length,
     // the indices after that will not correspond,
     // we therefore have to store the difference to correct this
@@ -101,17 +750,75 @@ pub fn replace_all<P: AsRef<Path>>(s: &str, path: P) -> Result<String> {
 
     for playpen in find_links(s) {
         replaced.push_str(&s[previous_end_index..playpen.start_index]);
-        replaced.push_str(&playpen.render_with_path(&path)?);
+        match playpen.render_with_path(&path, config, depth, context) {
+            Ok(rendered) => replaced.push_str(&rendered),
+            Err(e) => {
+                error!("{context}: {e:#}");
+                replaced.push_str(&playpen.link_text);
+            }
+        }
         previous_end_index = playpen.end_index;
     }
 
     replaced.push_str(&s[previous_end_index..]);
-    Ok(replaced)
+    replaced
+}
+
+#[cfg(test)]
+mod recursive_include_tests {
+    use super::*;
+    use std::fs;
+
+    fn test_config() -> PreprocessorConfig {
+        PreprocessorConfig {
+            features: vec!["test".to_string()],
+            rust_aware: false,
+            mode: Mode::Hide,
+        }
+    }
+
+    #[test]
+    fn nested_include_is_expanded_relative_to_its_own_directory() {
+        let dir = std::env::temp_dir().join("mdbook_hide_feature_test_nested_include");
+        // `inner.md` exists only next to `outer.md` in `sub/`, not in the
+        // chapter directory, so it resolves only relative to `outer.md`.
+        let sub = dir.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(sub.join("inner.md"), "inner content\n").unwrap();
+        fs::write(
+            sub.join("outer.md"),
+            "before\n{{#includehidetest inner.md}}after\n",
+        )
+        .unwrap();
+
+        let result = replace_all("{{#includehidetest sub/outer.md}}", &dir, &test_config(), 0, "test");
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, "before\ninner content\nafter\n");
+    }
+
+    #[test]
+    fn cyclic_includes_terminate_at_max_nested_depth() {
+        let dir = std::env::temp_dir().join("mdbook_hide_feature_test_cyclic_include");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.md"), "{{#includehidetest b.md}}\n").unwrap();
+        fs::write(dir.join("b.md"), "{{#includehidetest a.md}}\n").unwrap();
+
+        // A cyclic include chain must terminate instead of recursing forever;
+        // if MAX_LINK_NESTED_DEPTH weren't enforced this call would never
+        // return.
+        let result = replace_all("{{#includehidetest a.md}}", &dir, &test_config(), 0, "test");
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.contains("{{#includehidetest"));
+    }
 }
 
 #[derive(PartialOrd, PartialEq, Debug, Clone)]
 enum LinkType {
-    IncludeHideTest(PathBuf),
+    IncludeHideTest(PathBuf, Option<Selection>, BTreeMap<String, String>),
 }
 
 #[derive(PartialOrd, PartialEq, Debug, Clone)]
@@ -126,11 +833,16 @@ impl Link {
     fn from_capture(cap: Captures) -> Option<Link> {
         let link_type = match (cap.get(0), cap.get(1), cap.get(2)) {
             (_, Some(typ), Some(rest)) => {
-                let mut path_props = rest.as_str().split_whitespace();
-                let file_path = path_props.next().map(PathBuf::from);
+                let rest = rest.as_str().trim_start();
+                let split_at = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let (path_token, args_str) = rest.split_at(split_at);
+                let file_path = (!path_token.is_empty()).then(|| parse_selector(path_token));
+                let args = parse_args(args_str);
 
                 match (typ.as_str(), file_path) {
-                    ("includehidetest", Some(pth)) => Some(LinkType::IncludeHideTest(pth)),
+                    ("includehidetest", Some((pth, selection))) => {
+                        Some(LinkType::IncludeHideTest(pth, selection, args))
+                    }
                     _ => None,
                 }
             }
@@ -147,17 +859,62 @@ impl Link {
         })
     }
 
-    fn render_with_path<P: AsRef<Path>>(&self, base: P) -> Result<String> {
+    fn render_with_path<P: AsRef<Path>>(
+        &self,
+        base: P,
+        config: &PreprocessorConfig,
+        depth: usize,
+        context: &str,
+    ) -> Result<String> {
         let base = base.as_ref();
         match self.link {
             // omit the escape char
-            LinkType::IncludeHideTest(ref pat) => {
+            LinkType::IncludeHideTest(ref pat, ref selection, ref args) => {
                 // get file
-                let contents = std::fs::read_to_string(base.join(pat))?;
+                let full_path = base.join(pat);
+                let contents = std::fs::read_to_string(&full_path)
+                    .with_context(|| format!("could not read {}", full_path.display()))?;
+                // narrow down to the requested anchor or line range, if any
+                let contents = match selection {
+                    Some(Selection::LineRange(range)) => {
+                        take_lines(&contents, range.start, range.end)
+                    }
+                    Some(Selection::Anchor(name)) => match take_anchored_lines(&contents, name) {
+                        Some(selected) => selected,
+                        None => bail!("anchor {name:?} not found in {}", full_path.display()),
+                    },
+                    None => contents,
+                };
+                // `feature=name` and `mode=strip|show|hide` on the directive
+                // override the book-wide config
+                let features: Vec<String> = match args.get("feature") {
+                    Some(value) => value.split(',').map(|s| s.trim().to_string()).collect(),
+                    None => config.features.clone(),
+                };
+                let mode = args
+                    .get("mode")
+                    .and_then(|value| Mode::parse(value))
+                    .unwrap_or(config.mode);
                 // run regex above on it
-                let contents = filter_features(&contents, "test");
-                // give result
-                Ok(contents)
+                let contents = hide_features(&contents, &features, config.rust_aware, mode);
+
+                // expand any includes nested inside the included content,
+                // relative to its own directory, unless we've recursed too deep
+                if depth >= MAX_LINK_NESTED_DEPTH {
+                    warn!(
+                        "{context}: max include nesting depth ({MAX_LINK_NESTED_DEPTH}) reached, \
+                         leaving nested includes in {full_path:?} unexpanded"
+                    );
+                    return Ok(contents);
+                }
+                let nested_base = full_path.parent().unwrap_or(base);
+                Ok(replace_all(
+                    &contents,
+                    nested_base,
+                    config,
+                    depth + 1,
+                    context,
+                ))
                 //file_to_string(base.join(pat)).chain_err(|| format!("Could not read file for link {}", self.link_text))
             }
         }
@@ -178,7 +935,7 @@ impl<'a> Iterator for LinkIter<'a> {
     }
 }
 
-fn find_links(contents: &str) -> LinkIter {
+fn find_links(contents: &str) -> LinkIter<'_> {
     // lazily compute following regex
     // r"\\\{\{#.*\}\}|\{\{#([a-zA-Z0-9]+)\s*([a-zA-Z0-9_.\-:/\\\s]+)\}\}")?;
     lazy_static::lazy_static! {
@@ -188,35 +945,91 @@ fn find_links(contents: &str) -> LinkIter {
                     \{\{\s*                      # link opening parens and whitespace
                       \#([a-zA-Z0-9]+)           # link type
                       \s+                        # separating whitespace
-                      ([a-zA-Z0-9\s_.\-:/\\]+)   # link target path and space separated properties
+                      ([a-zA-Z0-9\s_.=\-:/\\,]+) # link target path and space separated key=value properties
+                                                  # (`,` allowed for feature=a,b overrides)
                     \s*\}\}                      # whitespace and link closing parens
                                  ").unwrap();
     }
     LinkIter(RE.captures_iter(contents))
 }
 
-fn main() {
-    init().unwrap();
-    let matches = make_app().get_matches();
-    if let Some(_sub_args) = matches.subcommand_matches("supports") {
-        std::process::exit(0);
+#[cfg(test)]
+mod link_tests {
+    use super::*;
+
+    #[test]
+    fn comma_separated_feature_override_is_matched_and_parsed() {
+        let directive = "{{#includehidetest file.rs feature=a,b}}";
+
+        let link = find_links(directive)
+            .next()
+            .expect("comma-separated feature= override should still match the link regex");
+
+        match link.link {
+            LinkType::IncludeHideTest(path, _selection, args) => {
+                assert_eq!(path, PathBuf::from("file.rs"));
+                assert_eq!(args.get("feature").map(String::as_str), Some("a,b"));
+            }
+        }
     }
+}
+
+/// Renderers this preprocessor can meaningfully serve. mdBook calls
+/// `supports <renderer>` before running us and skips us (rather than
+/// failing the build) if we exit non-zero.
+const SUPPORTED_RENDERERS: &[&str] = &["html", "markdown"];
+
+fn handle_supports(sub_args: &ArgMatches) -> ! {
+    let renderer = sub_args
+        .get_one::<String>("renderer")
+        .map(String::as_str)
+        .unwrap_or_default();
+    let supported = SUPPORTED_RENDERERS.contains(&renderer);
+    std::process::exit(if supported { 0 } else { 1 });
+}
+
+fn handle_preprocessing() -> Result<()> {
+    let (ctx, mut book) = CmdPreprocessor::parse_input(io::stdin())?;
+    let config = PreprocessorConfig::from_context(&ctx);
+
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            // Draft chapters (a `SUMMARY.md` entry with no linked file) have
+            // no path; there's nothing to resolve includes relative to, so
+            // leave them untouched rather than panicking.
+            let Some(parent) = chapter.path.as_ref().and_then(|p| p.parent()) else {
+                debug!("{}: draft chapter has no path, skipping", chapter.name);
+                return;
+            };
 
-    let (_ctx, mut book) = CmdPreprocessor::parse_input(io::stdin()).unwrap();
-    book.for_each_mut(|item| match item {
-        BookItem::Chapter(ref mut chapter) => {
             let old = chapter.content.clone();
             chapter.content = replace_all(
                 &chapter.content,
-                PathBuf::from("src").join(chapter.path.as_ref().and_then(|p| p.parent()).unwrap()),
-            )
-            .unwrap();
+                PathBuf::from("src").join(parent),
+                &config,
+                0,
+                &chapter.name,
+            );
             if chapter.content != old {
                 debug!("old:{}\nnew:{}", old, chapter.content);
             }
         }
-        _ => {}
     });
 
-    serde_json::to_writer(io::stdout(), &book).unwrap();
+    serde_json::to_writer(io::stdout(), &book)?;
+    Ok(())
+}
+
+fn main() {
+    init().unwrap();
+    let matches = make_app().get_matches();
+
+    if let Some(sub_args) = matches.subcommand_matches("supports") {
+        handle_supports(sub_args);
+    }
+
+    if let Err(e) = handle_preprocessing() {
+        error!("{e:#}");
+        std::process::exit(1);
+    }
 }